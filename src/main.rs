@@ -1,6 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::time::Duration;
+use tokio::time::{Instant, Sleep};
 use warp::Filter;
 
 #[cfg(test)]
@@ -10,12 +15,27 @@ mod tests {
     use warp::test::request;
     use tokio::time::{sleep, Duration};
 
+    fn test_state() -> SharedState {
+        Arc::new(AppState {
+            barriers: Mutex::new(HashMap::new()),
+            config: Config {
+                default_timeout: Duration::from_secs(10),
+            },
+            http_client: reqwest::Client::new(),
+            single_flight: Mutex::new(HashMap::new()),
+            relay: RelayState::new(),
+        })
+    }
+
     #[tokio::test]
     async fn test_sync_point() {
-        let state = Arc::new(Mutex::new(HashMap::new()));
+        let state = test_state();
         let state_filter = warp::any().map(move || Arc::clone(&state));
 
         let sync_route = warp::path!("wait-for-second-party" / String)
+            .and(warp::query::<SyncQuery>())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
             .and(state_filter.clone())
             .and(warp::post())
             .and_then(handle_sync);
@@ -26,6 +46,7 @@ mod tests {
                 request()
                     .method("POST")
                     .path("/wait-for-second-party/test-id")
+                    .body("hello from A")
                     .reply(&sync_route)
                     .await
             }
@@ -40,6 +61,7 @@ mod tests {
                 request()
                     .method("POST")
                     .path("/wait-for-second-party/test-id")
+                    .body("hello from B")
                     .reply(&sync_route)
                     .await
             }
@@ -49,81 +71,1122 @@ mod tests {
         let response2 = response2.await.unwrap();
 
         assert_eq!(response1.status(), StatusCode::OK);
-        assert_eq!(response1.body(), "Synced");
+        assert_eq!(response1.body(), "hello from B");
 
         assert_eq!(response2.status(), StatusCode::OK);
-        assert_eq!(response2.body(), "Synced");
+        assert_eq!(response2.body(), "hello from A");
     }
 
     #[tokio::test]
-    async fn test_timeout() {
-        let state = Arc::new(Mutex::new(HashMap::new()));
+    async fn test_n_party_barrier() {
+        let state = test_state();
         let state_filter = warp::any().map(move || Arc::clone(&state));
 
         let sync_route = warp::path!("wait-for-second-party" / String)
+            .and(warp::query::<SyncQuery>())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
             .and(state_filter.clone())
             .and(warp::post())
             .and_then(handle_sync);
 
-        let response1 = tokio::spawn({
+        let bodies = ["party-0", "party-1", "party-2"];
+        let mut handles = Vec::new();
+        for body in bodies {
+            let sync_route = sync_route.clone();
+            handles.push(tokio::spawn(async move {
+                request()
+                    .method("POST")
+                    .path("/wait-for-second-party/party-id?parties=3")
+                    .body(body)
+                    .reply(&sync_route)
+                    .await
+            }));
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let response = handle.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = String::from_utf8(response.body().to_vec()).unwrap();
+            for (j, other) in bodies.iter().enumerate() {
+                if i == j {
+                    assert!(!body.contains(other));
+                } else {
+                    assert!(body.contains(other));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_rejects_party_count_mismatch() {
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let sync_route = warp::path!("wait-for-second-party" / String)
+            .and(warp::query::<SyncQuery>())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_sync);
+
+        let first = tokio::spawn({
             let sync_route = sync_route.clone();
             async move {
                 request()
                     .method("POST")
-                    .path("/wait-for-second-party/timeout-id")
+                    .path("/wait-for-second-party/mismatch-id?parties=5&timeout_ms=100")
+                    .body("party-0")
                     .reply(&sync_route)
                     .await
             }
         });
 
-        // Wait longer than the timeout duration
-        sleep(Duration::from_secs(11)).await;
+        sleep(Duration::from_millis(20)).await;
 
-        let response1 = response1.await.unwrap();
+        let second = request()
+            .method("POST")
+            .path("/wait-for-second-party/mismatch-id?parties=2")
+            .body("party-1")
+            .reply(&sync_route)
+            .await;
+
+        assert_eq!(second.status(), StatusCode::BAD_REQUEST);
+
+        // The original 5-party barrier is untouched and still waiting.
+        let first = first.await.unwrap();
+        assert_eq!(first.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let sync_route = warp::path!("wait-for-second-party" / String)
+            .and(warp::query::<SyncQuery>())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_sync);
+
+        let response1 = request()
+            .method("POST")
+            .path("/wait-for-second-party/timeout-id?timeout_ms=50")
+            .reply(&sync_route)
+            .await;
 
         assert_eq!(response1.status(), StatusCode::REQUEST_TIMEOUT);
         assert_eq!(response1.body(), "Timeout");
     }
+
+    #[tokio::test]
+    async fn test_single_flight_fetch_coalesces_concurrent_requests() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let call_count = Arc::new(AtomicUsize::new(0));
+        let call_count_filter = {
+            let call_count = Arc::clone(&call_count);
+            warp::any().map(move || Arc::clone(&call_count))
+        };
+        let upstream_route = warp::path!("slow").and(call_count_filter).and_then(
+            |call_count: Arc<AtomicUsize>| async move {
+                call_count.fetch_add(1, Ordering::SeqCst);
+                sleep(Duration::from_millis(200)).await;
+                Ok::<_, std::convert::Infallible>("upstream-body")
+            },
+        );
+        let (addr, upstream) = warp::serve(upstream_route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(upstream);
+
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let fetch_route = warp::path!("fetch")
+            .and(warp::query::<FetchQuery>())
+            .and(state_filter.clone())
+            .and(warp::get())
+            .and_then(handle_fetch);
+
+        let url = format!("http://{}/slow", addr);
+
+        let mut handles = Vec::new();
+        for _ in 0..5 {
+            let fetch_route = fetch_route.clone();
+            let path = format!("/fetch?url={}", url);
+            handles.push(tokio::spawn(async move {
+                request().method("GET").path(&path).reply(&fetch_route).await
+            }));
+        }
+
+        for handle in handles {
+            let response = handle.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.body(), "upstream-body");
+        }
+
+        assert_eq!(call_count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_single_flight_leader_cancellation_releases_followers() {
+        let upstream_route =
+            warp::path!("slow").and_then(|| async move {
+                sleep(Duration::from_secs(5)).await;
+                Ok::<_, std::convert::Infallible>("upstream-body")
+            });
+        let (addr, upstream) = warp::serve(upstream_route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(upstream);
+
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let fetch_route = warp::path!("fetch")
+            .and(warp::query::<FetchQuery>())
+            .and(state_filter.clone())
+            .and(warp::get())
+            .and_then(handle_fetch);
+
+        let path = format!("/fetch?url=http://{}/slow", addr);
+
+        let leader = tokio::spawn({
+            let fetch_route = fetch_route.clone();
+            let path = path.clone();
+            async move { request().method("GET").path(&path).reply(&fetch_route).await }
+        });
+
+        // Let the leader register itself and start the upstream request.
+        sleep(Duration::from_millis(50)).await;
+
+        let follower = tokio::spawn({
+            let fetch_route = fetch_route.clone();
+            let path = path.clone();
+            async move { request().method("GET").path(&path).reply(&fetch_route).await }
+        });
+
+        sleep(Duration::from_millis(50)).await;
+        leader.abort();
+
+        let follower_response = follower.await.unwrap();
+        assert_eq!(follower_response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_relay_round_trip() {
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let relay_submit_route = warp::path!("relay" / String)
+            .and(warp::query::<TimeoutQuery>())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_relay_submit);
+
+        let relay_poll_route = warp::path!("poll" / String)
+            .and(warp::query::<TimeoutQuery>())
+            .and(state_filter.clone())
+            .and(warp::get())
+            .and_then(handle_relay_poll);
+
+        let relay_respond_route = warp::path!("respond" / String)
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_relay_respond);
+
+        let caller = tokio::spawn({
+            let relay_submit_route = relay_submit_route.clone();
+            async move {
+                request()
+                    .method("POST")
+                    .path("/relay/job-1")
+                    .body("work-item")
+                    .reply(&relay_submit_route)
+                    .await
+            }
+        });
+
+        // Give the caller a chance to park its request before we poll for it.
+        sleep(Duration::from_millis(50)).await;
+
+        let poll_response = request()
+            .method("GET")
+            .path("/poll/job-1")
+            .reply(&relay_poll_route)
+            .await;
+
+        assert_eq!(poll_response.status(), StatusCode::OK);
+        assert_eq!(poll_response.body(), "work-item");
+
+        let request_key = poll_response
+            .headers()
+            .get("x-relay-request-key")
+            .expect("poll response carries the request key")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let respond_path = format!("/respond/{}", request_key);
+        let respond_response = request()
+            .method("POST")
+            .path(&respond_path)
+            .body("answer")
+            .reply(&relay_respond_route)
+            .await;
+        assert_eq!(respond_response.status(), StatusCode::OK);
+
+        let caller_response = caller.await.unwrap();
+        assert_eq!(caller_response.status(), StatusCode::OK);
+        assert_eq!(caller_response.body(), "answer");
+    }
+
+    #[tokio::test]
+    async fn test_relay_poll_times_out_without_a_request() {
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let relay_poll_route = warp::path!("poll" / String)
+            .and(warp::query::<TimeoutQuery>())
+            .and(state_filter.clone())
+            .and(warp::get())
+            .and_then(handle_relay_poll);
+
+        let poll_response = request()
+            .method("GET")
+            .path("/poll/job-none?timeout_ms=50")
+            .reply(&relay_poll_route)
+            .await;
+
+        assert_eq!(poll_response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(poll_response.body(), "Timeout");
+    }
+
+    #[tokio::test]
+    async fn test_relay_respond_after_caller_disconnects_is_gone() {
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let relay_submit_route = warp::path!("relay" / String)
+            .and(warp::query::<TimeoutQuery>())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_relay_submit);
+
+        let relay_poll_route = warp::path!("poll" / String)
+            .and(warp::query::<TimeoutQuery>())
+            .and(state_filter.clone())
+            .and(warp::get())
+            .and_then(handle_relay_poll);
+
+        let relay_respond_route = warp::path!("respond" / String)
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_relay_respond);
+
+        let caller = tokio::spawn({
+            let relay_submit_route = relay_submit_route.clone();
+            async move {
+                request()
+                    .method("POST")
+                    .path("/relay/job-gone")
+                    .body("work-item")
+                    .reply(&relay_submit_route)
+                    .await
+            }
+        });
+
+        // Give the caller a chance to park its request before we poll for it.
+        sleep(Duration::from_millis(20)).await;
+
+        let poll_response = request()
+            .method("GET")
+            .path("/poll/job-gone")
+            .reply(&relay_poll_route)
+            .await;
+        assert_eq!(poll_response.status(), StatusCode::OK);
+
+        let request_key = poll_response
+            .headers()
+            .get("x-relay-request-key")
+            .expect("poll response carries the request key")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // Simulate the caller disconnecting (warp would cancel its handler
+        // future) instead of timing out through handle_relay_submit's own
+        // cleanup path, leaving its claimed in_flight entry stranded.
+        caller.abort();
+        let _ = caller.await;
+
+        let respond_path = format!("/respond/{}", request_key);
+        let respond_response = request()
+            .method("POST")
+            .path(&respond_path)
+            .body("answer")
+            .reply(&relay_respond_route)
+            .await;
+        assert_eq!(respond_response.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn test_relay_submit_timeout_releases_claimed_in_flight_entry() {
+        let state = test_state();
+        let state_filter = warp::any().map(move || Arc::clone(&state));
+
+        let relay_submit_route = warp::path!("relay" / String)
+            .and(warp::query::<TimeoutQuery>())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_relay_submit);
+
+        let relay_poll_route = warp::path!("poll" / String)
+            .and(warp::query::<TimeoutQuery>())
+            .and(state_filter.clone())
+            .and(warp::get())
+            .and_then(handle_relay_poll);
+
+        let relay_respond_route = warp::path!("respond" / String)
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .and(state_filter.clone())
+            .and(warp::post())
+            .and_then(handle_relay_respond);
+
+        let caller = tokio::spawn({
+            let relay_submit_route = relay_submit_route.clone();
+            async move {
+                request()
+                    .method("POST")
+                    .path("/relay/job-abandoned?timeout_ms=50")
+                    .body("work-item")
+                    .reply(&relay_submit_route)
+                    .await
+            }
+        });
+
+        sleep(Duration::from_millis(20)).await;
+
+        let poll_response = request()
+            .method("GET")
+            .path("/poll/job-abandoned")
+            .reply(&relay_poll_route)
+            .await;
+        assert_eq!(poll_response.status(), StatusCode::OK);
+
+        let request_key = poll_response
+            .headers()
+            .get("x-relay-request-key")
+            .expect("poll response carries the request key")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        // The caller's own timeout fires (the worker never calls /respond).
+        let caller_response = caller.await.unwrap();
+        assert_eq!(caller_response.status(), StatusCode::REQUEST_TIMEOUT);
+
+        // The claimed in_flight entry was cleaned up alongside it, rather
+        // than staying around forever waiting for a /respond that may
+        // never come.
+        let respond_path = format!("/respond/{}", request_key);
+        let respond_response = request()
+            .method("POST")
+            .path(&respond_path)
+            .body("answer")
+            .reply(&relay_respond_route)
+            .await;
+        assert_eq!(respond_response.status(), StatusCode::NOT_FOUND);
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    let state = Arc::new(Mutex::new(HashMap::new()));
+    let state: SharedState = Arc::new(AppState {
+        barriers: Mutex::new(HashMap::new()),
+        config: Config::from_env(),
+        http_client: reqwest::Client::new(),
+        single_flight: Mutex::new(HashMap::new()),
+        relay: RelayState::new(),
+    });
     let state_filter = warp::any().map(move || Arc::clone(&state));
 
     let sync_route = warp::path!("wait-for-second-party" / String)
+        .and(warp::query::<SyncQuery>())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
         .and(state_filter.clone())
         .and(warp::post())
         .and_then(handle_sync);
 
-    warp::serve(sync_route).run(([127, 0, 0, 1], 3030)).await;
+    let fetch_route = warp::path!("fetch")
+        .and(warp::query::<FetchQuery>())
+        .and(state_filter.clone())
+        .and(warp::get())
+        .and_then(handle_fetch);
+
+    let relay_submit_route = warp::path!("relay" / String)
+        .and(warp::query::<TimeoutQuery>())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and(warp::post())
+        .and_then(handle_relay_submit);
+
+    let relay_poll_route = warp::path!("poll" / String)
+        .and(warp::query::<TimeoutQuery>())
+        .and(state_filter.clone())
+        .and(warp::get())
+        .and_then(handle_relay_poll);
+
+    let relay_respond_route = warp::path!("respond" / String)
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and(warp::post())
+        .and_then(handle_relay_respond);
+
+    let routes = sync_route
+        .or(fetch_route)
+        .or(relay_submit_route)
+        .or(relay_poll_route)
+        .or(relay_respond_route);
+
+    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+}
+
+/// Server-wide configuration, read once at startup.
+struct Config {
+    default_timeout: Duration,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let default_timeout_ms = std::env::var("SYNC_DEFAULT_TIMEOUT_MS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(10_000);
+
+        Config {
+            default_timeout: Duration::from_millis(default_timeout_ms),
+        }
+    }
+}
+
+struct AppState {
+    barriers: Mutex<HashMap<String, Barrier>>,
+    config: Config,
+    http_client: reqwest::Client,
+    single_flight: Mutex<HashMap<String, Vec<tokio::sync::oneshot::Sender<FetchResult>>>>,
+    relay: RelayState,
+}
+
+type SharedState = Arc<AppState>;
+
+/// A resettable deadline timer.
+///
+/// Wraps a `Sleep` so its firing point can be pushed out (`set`) or parked
+/// far in the future (`clear`) without dropping and recreating the
+/// underlying timer. This is what lets an N-party barrier extend its window
+/// every time a new party arrives, instead of every waiter racing the fixed
+/// deadline that happened to be in force when it showed up.
+struct Timeout {
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Timeout {
+    fn new(duration: Duration) -> Self {
+        Timeout {
+            sleep: Box::pin(tokio::time::sleep(duration)),
+        }
+    }
+
+    fn set(&mut self, deadline: Instant) {
+        self.sleep.as_mut().reset(deadline);
+    }
+
+    fn clear(&mut self) {
+        self.set(Instant::now() + Duration::from_secs(365 * 24 * 3600));
+    }
+}
+
+impl Future for Timeout {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.sleep.as_mut().poll(cx)
+    }
+}
+
+/// The body a party hands in, and what it should be handed back out as.
+#[derive(Clone, Default)]
+struct Payload {
+    body: Vec<u8>,
+    content_type: Option<String>,
+}
+
+/// A barrier waiter: the channel used to wake it up, plus the payload it
+/// brought with it so other arrivals can be handed it back.
+struct WaiterSlot {
+    tx: tokio::sync::oneshot::Sender<Payload>,
+    payload: Payload,
+}
+
+/// A barrier waiting for `parties` callers to arrive under the same id.
+///
+/// Waiters are keyed by a monotonically increasing id so a single waiter
+/// can be pulled back out of `waiters` if it times out, without disturbing
+/// anyone else still waiting on the same barrier. `deadline` is shared by
+/// every current waiter so a fresh arrival can push the whole barrier's
+/// timeout back via `Timeout::set`.
+struct Barrier {
+    parties: usize,
+    next_waiter_id: u64,
+    waiters: HashMap<u64, WaiterSlot>,
+    deadline: Arc<Mutex<Instant>>,
+}
+
+impl Barrier {
+    fn new(parties: usize, timeout: Duration) -> Self {
+        Barrier {
+            parties,
+            next_waiter_id: 0,
+            waiters: HashMap::new(),
+            deadline: Arc::new(Mutex::new(Instant::now() + timeout)),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SyncQuery {
+    #[serde(default = "default_parties")]
+    parties: usize,
+    timeout_ms: Option<u64>,
+}
+
+fn default_parties() -> usize {
+    2
+}
+
+/// Combine the payloads of every other party into the one this waiter gets
+/// back. For the common two-party case this is just the other party's body
+/// and content-type, unchanged. For more than two parties there is no single
+/// "other" body, so the payloads are newline-joined and the content-type is
+/// kept only if every one of those other parties used the same one.
+fn combine_payloads(others: Vec<Payload>) -> Payload {
+    let mut others = others;
+    if others.len() == 1 {
+        return others.remove(0);
+    }
+
+    let content_type = others.first().and_then(|p| p.content_type.clone());
+    let uniform_content_type = others.iter().all(|p| p.content_type == content_type);
+
+    let mut body = Vec::new();
+    for (i, payload) in others.iter().enumerate() {
+        if i > 0 {
+            body.push(b'\n');
+        }
+        body.extend_from_slice(&payload.body);
+    }
+
+    Payload {
+        body,
+        content_type: if uniform_content_type {
+            content_type
+        } else {
+            None
+        },
+    }
+}
+
+fn reply_with_payload(
+    status: warp::http::StatusCode,
+    payload: Payload,
+) -> warp::http::Response<warp::hyper::Body> {
+    let mut builder = warp::http::Response::builder().status(status);
+    if let Some(content_type) = payload.content_type {
+        builder = builder.header("content-type", content_type);
+    }
+    builder.body(warp::hyper::Body::from(payload.body)).unwrap()
 }
 
 async fn handle_sync(
     unique_id: String,
-    state: Arc<Mutex<HashMap<String, tokio::sync::oneshot::Sender<()>>>>,
+    query: SyncQuery,
+    content_type: Option<String>,
+    body: bytes::Bytes,
+    state: SharedState,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let (tx, rx) = tokio::sync::oneshot::channel();
+    let (tx, mut rx) = tokio::sync::oneshot::channel();
+    let waiter_id;
+    let payload = Payload {
+        body: body.to_vec(),
+        content_type,
+    };
+    let timeout_duration = query
+        .timeout_ms
+        .map(Duration::from_millis)
+        .unwrap_or(state.config.default_timeout);
+    let deadline_handle;
 
     {
-        let mut state_lock = state.lock().unwrap();
-        if let Some(tx_existing) = state_lock.remove(&unique_id) {
-            tx_existing.send(()).unwrap_or_default();
-            return Ok(warp::reply::with_status("Synced", warp::http::StatusCode::OK));
+        let mut barriers = state.barriers.lock().unwrap();
+
+        if let Some(existing) = barriers.get(&unique_id) {
+            if existing.parties != query.parties {
+                return Ok(reply_with_payload(
+                    warp::http::StatusCode::BAD_REQUEST,
+                    Payload {
+                        body: format!(
+                            "barrier {unique_id} already waiting for {} parties, got parties={}",
+                            existing.parties, query.parties
+                        )
+                        .into_bytes(),
+                        content_type: None,
+                    },
+                ));
+            }
+        }
+
+        let barrier = barriers
+            .entry(unique_id.clone())
+            .or_insert_with(|| Barrier::new(query.parties, timeout_duration));
+
+        waiter_id = barrier.next_waiter_id;
+        barrier.next_waiter_id += 1;
+        barrier.waiters.insert(waiter_id, WaiterSlot { tx, payload });
+        *barrier.deadline.lock().unwrap() = Instant::now() + timeout_duration;
+        deadline_handle = Arc::clone(&barrier.deadline);
+
+        if barrier.waiters.len() >= barrier.parties {
+            let barrier = barriers.remove(&unique_id).unwrap();
+            let slots: Vec<WaiterSlot> = barrier.waiters.into_values().collect();
+            let payloads: Vec<Payload> = slots.iter().map(|slot| slot.payload.clone()).collect();
+
+            for (i, slot) in slots.into_iter().enumerate() {
+                let others = payloads
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, payload)| payload.clone())
+                    .collect();
+                slot.tx.send(combine_payloads(others)).unwrap_or_default();
+            }
+        }
+    }
+
+    let mut timeout = Timeout::new(timeout_duration);
+    loop {
+        tokio::select! {
+            result = &mut rx => {
+                timeout.clear();
+                return Ok(reply_with_payload(warp::http::StatusCode::OK, result.unwrap_or_default()));
+            },
+            _ = &mut timeout => {
+                let current_deadline = *deadline_handle.lock().unwrap();
+                if current_deadline > Instant::now() {
+                    // Another party arrived and pushed the barrier's deadline
+                    // out while we were sleeping; keep waiting.
+                    timeout.set(current_deadline);
+                    continue;
+                }
+
+                let mut barriers = state.barriers.lock().unwrap();
+                let mut drop_entry = false;
+                if let Some(barrier) = barriers.get_mut(&unique_id) {
+                    barrier.waiters.remove(&waiter_id);
+                    drop_entry = barrier.waiters.is_empty();
+                }
+                if drop_entry {
+                    barriers.remove(&unique_id);
+                }
+                return Ok(reply_with_payload(
+                    warp::http::StatusCode::REQUEST_TIMEOUT,
+                    Payload { body: b"Timeout".to_vec(), content_type: None },
+                ));
+            },
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct FetchQuery {
+    url: String,
+}
+
+/// A small, cheaply `Clone`-able stand-in for `reqwest::Response` so a
+/// single upstream fetch can be fanned out to every waiter without
+/// re-reading the body.
+#[derive(Clone)]
+struct FetchResponse {
+    status: u16,
+    body: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct FetchError {
+    message: String,
+}
+
+type FetchResult = Result<FetchResponse, FetchError>;
+
+async fn fetch_upstream(client: &reqwest::Client, url: &str) -> FetchResult {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| FetchError { message: err.to_string() })?;
+    let status = response.status().as_u16();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| FetchError { message: err.to_string() })?
+        .to_vec();
+    Ok(FetchResponse { status, body })
+}
+
+fn fetch_reply(result: FetchResult) -> warp::http::Response<warp::hyper::Body> {
+    match result {
+        Ok(response) => warp::http::Response::builder()
+            .status(
+                warp::http::StatusCode::from_u16(response.status)
+                    .unwrap_or(warp::http::StatusCode::OK),
+            )
+            .body(warp::hyper::Body::from(response.body))
+            .unwrap(),
+        Err(err) => warp::http::Response::builder()
+            .status(warp::http::StatusCode::BAD_GATEWAY)
+            .body(warp::hyper::Body::from(err.message))
+            .unwrap(),
+    }
+}
+
+/// Releases a single-flight leader's waiters if the leader's own handler
+/// future is dropped (e.g. warp cancels it because the client disconnected)
+/// before it reaches the point where it would normally drain and notify
+/// them itself. Without this, a cancelled leader leaves its followers
+/// hanging on a `rx.await` that will never resolve, and leaves the `url`
+/// entry in `single_flight` forever, poisoning every future request for it.
+struct FetchLeaderGuard {
+    state: SharedState,
+    url: String,
+    completed: bool,
+}
+
+impl FetchLeaderGuard {
+    fn new(state: SharedState, url: String) -> Self {
+        FetchLeaderGuard {
+            state,
+            url,
+            completed: false,
+        }
+    }
+
+    fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for FetchLeaderGuard {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        let waiters = {
+            let mut in_flight = self.state.single_flight.lock().unwrap();
+            in_flight.remove(&self.url).unwrap_or_default()
+        };
+        for waiter in waiters {
+            waiter
+                .send(Err(FetchError {
+                    message: "single-flight leader was cancelled".to_string(),
+                }))
+                .unwrap_or_default();
+        }
+    }
+}
+
+/// Deduplicate concurrent fetches of the same URL: the first caller for a
+/// given `url` performs the upstream request, and every caller that arrives
+/// while it's in flight just queues up for a clone of its result. The lock
+/// is never held across the `.await` on the network call, and the queue
+/// entry is always drained and removed afterwards, even on upstream error
+/// or the leader being cancelled mid-fetch, so a later request re-triggers
+/// a fresh fetch. Followers race their wait against the configured timeout
+/// too, so a stuck leader can't hang them forever.
+async fn handle_fetch(
+    query: FetchQuery,
+    state: SharedState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let url = query.url;
+
+    let existing_waiter = {
+        let mut in_flight = state.single_flight.lock().unwrap();
+        if let Some(waiters) = in_flight.get_mut(&url) {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            waiters.push(tx);
+            Some(rx)
         } else {
-            state_lock.insert(unique_id.clone(), tx);
+            in_flight.insert(url.clone(), Vec::new());
+            None
+        }
+    };
+
+    let result = if let Some(rx) = existing_waiter {
+        tokio::select! {
+            result = rx => result.unwrap_or_else(|_| Err(FetchError {
+                message: "single-flight leader dropped the request".to_string(),
+            })),
+            _ = tokio::time::sleep(state.config.default_timeout) => Err(FetchError {
+                message: "timed out waiting for single-flight leader".to_string(),
+            }),
+        }
+    } else {
+        let guard = FetchLeaderGuard::new(Arc::clone(&state), url.clone());
+        let result = fetch_upstream(&state.http_client, &url).await;
+
+        let waiters = {
+            let mut in_flight = state.single_flight.lock().unwrap();
+            in_flight.remove(&url).unwrap_or_default()
+        };
+        for waiter in waiters {
+            waiter.send(result.clone()).unwrap_or_default();
         }
+        guard.complete();
+
+        result
+    };
+
+    Ok(fetch_reply(result))
+}
+
+#[derive(serde::Deserialize)]
+struct TimeoutQuery {
+    timeout_ms: Option<u64>,
+}
+
+impl TimeoutQuery {
+    fn duration_or(&self, default: Duration) -> Duration {
+        self.timeout_ms.map(Duration::from_millis).unwrap_or(default)
     }
+}
 
-    let timeout = tokio::time::sleep(Duration::from_secs(10));
-    tokio::select! {
-        _ = rx => {
-            Ok(warp::reply::with_status("Synced", warp::http::StatusCode::OK))
-        },
-        _ = timeout => {
-            let mut state_lock = state.lock().unwrap();
-            state_lock.remove(&unique_id);
-            Ok(warp::reply::with_status("Timeout", warp::http::StatusCode::REQUEST_TIMEOUT))
+/// A unit of work parked by `/relay/{id}` until a poller claims it.
+///
+/// `claimed_key` starts empty and is filled in by `handle_relay_poll` with
+/// the ULID request key it files `response_tx` under in `in_flight`, once
+/// that insertion has happened. The submitter keeps its own clone so that,
+/// if it times out after the job has already been claimed, it can find and
+/// drop the matching `in_flight` entry instead of leaking it.
+struct RelayJob {
+    submission_id: u64,
+    payload: Payload,
+    response_tx: tokio::sync::oneshot::Sender<Payload>,
+    claimed_key: Arc<Mutex<Option<String>>>,
+}
+
+/// State for the long-poll relay: work submitted via `/relay/{id}` sits in
+/// `pending` until a worker claims it through `/poll/{id}`. Claiming moves
+/// the job's response channel into `in_flight` under a fresh ULID request
+/// key, which `/respond/{key}` later uses to deliver the worker's answer
+/// back to the original, still-blocked caller.
+struct RelayState {
+    pending: Mutex<HashMap<String, VecDeque<RelayJob>>>,
+    notify: Mutex<HashMap<String, Arc<tokio::sync::Notify>>>,
+    in_flight: Mutex<HashMap<String, tokio::sync::oneshot::Sender<Payload>>>,
+    next_submission_id: AtomicU64,
+}
+
+impl RelayState {
+    fn new() -> Self {
+        RelayState {
+            pending: Mutex::new(HashMap::new()),
+            notify: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            next_submission_id: AtomicU64::new(0),
+        }
+    }
+
+    fn notifier_for(&self, id: &str) -> Arc<tokio::sync::Notify> {
+        let mut notify = self.notify.lock().unwrap();
+        Arc::clone(
+            notify
+                .entry(id.to_string())
+                .or_insert_with(|| Arc::new(tokio::sync::Notify::new())),
+        )
+    }
+}
+
+fn timeout_reply() -> warp::http::Response<warp::hyper::Body> {
+    reply_with_payload(
+        warp::http::StatusCode::REQUEST_TIMEOUT,
+        Payload {
+            body: b"Timeout".to_vec(),
+            content_type: None,
         },
+    )
+}
+
+/// Submit work under `id` and block until a poller (`handle_relay_poll`)
+/// claims it and a response comes back through `handle_relay_respond`.
+async fn handle_relay_submit(
+    id: String,
+    query: TimeoutQuery,
+    content_type: Option<String>,
+    body: bytes::Bytes,
+    state: SharedState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+    let submission_id = state.relay.next_submission_id.fetch_add(1, Ordering::SeqCst);
+    let claimed_key: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let payload = Payload {
+        body: body.to_vec(),
+        content_type,
+    };
+
+    {
+        let mut pending = state.relay.pending.lock().unwrap();
+        pending.entry(id.clone()).or_default().push_back(RelayJob {
+            submission_id,
+            payload,
+            response_tx,
+            claimed_key: Arc::clone(&claimed_key),
+        });
+    }
+    state.relay.notifier_for(&id).notify_one();
+
+    let timeout_duration = query.duration_or(state.config.default_timeout);
+
+    tokio::select! {
+        result = response_rx => Ok(reply_with_payload(warp::http::StatusCode::OK, result.unwrap_or_default())),
+        _ = tokio::time::sleep(timeout_duration) => {
+            {
+                let mut pending = state.relay.pending.lock().unwrap();
+                if let Some(queue) = pending.get_mut(&id) {
+                    queue.retain(|job| job.submission_id != submission_id);
+                    if queue.is_empty() {
+                        pending.remove(&id);
+                        state.relay.notify.lock().unwrap().remove(&id);
+                    }
+                }
+            }
+            // If a poller had already claimed this job, its sender is parked
+            // in `in_flight` under the key it was handed out. Nobody is left
+            // to read a response through it now, so drop it rather than
+            // leaving it there until (if ever) a worker calls /respond.
+            if let Some(key) = claimed_key.lock().unwrap().take() {
+                state.relay.in_flight.lock().unwrap().remove(&key);
+            }
+            Ok(timeout_reply())
+        }
+    }
+}
+
+/// Long-poll for work queued under `id`. Once a job is available it is
+/// handed out with a fresh request key (carried in the `x-relay-request-key`
+/// header) that `/respond/{key}` later uses to route the answer back.
+async fn handle_relay_poll(
+    id: String,
+    query: TimeoutQuery,
+    state: SharedState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let deadline = Instant::now() + query.duration_or(state.config.default_timeout);
+
+    loop {
+        let job = {
+            let mut pending = state.relay.pending.lock().unwrap();
+            let job = pending.get_mut(&id).and_then(|queue| queue.pop_front());
+            if pending.get(&id).is_some_and(|queue| queue.is_empty()) {
+                pending.remove(&id);
+            }
+            job
+        };
+
+        if let Some(job) = job {
+            let request_key = ulid::Ulid::new().to_string();
+            // Insert into `in_flight` before publishing the key to the
+            // submitter's `claimed_key`, so a concurrent submit timeout can
+            // never observe "claimed" before the entry it needs to remove
+            // actually exists.
+            state
+                .relay
+                .in_flight
+                .lock()
+                .unwrap()
+                .insert(request_key.clone(), job.response_tx);
+            *job.claimed_key.lock().unwrap() = Some(request_key.clone());
+
+            let mut response = reply_with_payload(warp::http::StatusCode::OK, job.payload);
+            response.headers_mut().insert(
+                "x-relay-request-key",
+                warp::http::HeaderValue::from_str(&request_key).unwrap(),
+            );
+            return Ok(response);
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(timeout_reply());
+        }
+
+        let notifier = state.relay.notifier_for(&id);
+        tokio::select! {
+            _ = notifier.notified() => continue,
+            _ = tokio::time::sleep(remaining) => return Ok(timeout_reply()),
+        }
+    }
+}
+
+/// Deliver a worker's answer, identified by the request key handed out by
+/// `handle_relay_poll`, to whichever caller is still blocked in
+/// `handle_relay_submit`.
+async fn handle_relay_respond(
+    request_key: String,
+    content_type: Option<String>,
+    body: bytes::Bytes,
+    state: SharedState,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let sender = state.relay.in_flight.lock().unwrap().remove(&request_key);
+    match sender {
+        Some(tx) => {
+            let payload = Payload {
+                body: body.to_vec(),
+                content_type,
+            };
+            match tx.send(payload) {
+                Ok(()) => Ok(reply_with_payload(warp::http::StatusCode::OK, Payload::default())),
+                // The original caller already gave up (timed out or
+                // disconnected) and dropped its receiver, so this answer
+                // has nowhere to go. Tell the worker it wasn't delivered
+                // rather than lying with a 200.
+                Err(_) => Ok(reply_with_payload(
+                    warp::http::StatusCode::GONE,
+                    Payload {
+                        body: b"Caller is no longer waiting for this response".to_vec(),
+                        content_type: None,
+                    },
+                )),
+            }
+        }
+        None => Ok(reply_with_payload(
+            warp::http::StatusCode::NOT_FOUND,
+            Payload {
+                body: b"Unknown request key".to_vec(),
+                content_type: None,
+            },
+        )),
     }
 }